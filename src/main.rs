@@ -1,41 +1,247 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, HashMap, HashSet},
     io::{self, stdout, BufWriter, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 use itertools::Itertools;
-use log::info;
-use osmpbfreader::{OsmId, OsmObj, OsmPbfReader};
-use serde_json::to_string;
+use log::{info, warn};
+use osmpbfreader::{NodeId, OsmId, OsmObj, OsmPbfReader, Relation};
+use rayon::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, to_string, Value};
+use sha3::{Digest, Sha3_256};
 use simple_logger::SimpleLogger;
 
-const TARGET_BOUNDARY_TYPES: &[&str] = &[
+/// `boundary` tag values kept when `--boundary-type` is not given.
+const DEFAULT_BOUNDARY_TYPES: &[&str] = &[
     "administrative",
     "state_border",
     "country_border",
     "state border",
 ];
 
+/// `admin_level` tag values kept when `--admin-level` is not given.
+const DEFAULT_ADMIN_LEVELS: &[&str] = &["2", "4", "6", "7", "8"];
+
 #[derive(Parser)]
 struct Cli {
-    /// PBF file to read.
-    #[arg(short, long)]
-    in_file: PathBuf,
+    /// PBF file(s) to read. Pass more than one to process them in bulk.
+    #[arg(short, long, required = true)]
+    in_file: Vec<PathBuf>,
 
     /// Path to output file. If unspecified output is written to stdout.
     #[arg(short, long)]
     out_file: Option<PathBuf>,
 
+    /// Restrict output to relations whose geometry intersects this box,
+    /// given as `minlon,minlat,maxlon,maxlat`.
+    #[arg(long)]
+    bbox: Option<BBox>,
+
+    /// Number of threads to load relations with. Defaults to the number of
+    /// CPUs.
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// With `stats` over multiple input files, write one CSV row per input
+    /// to `out_file` instead of the default text report.
+    #[arg(long)]
+    csv: bool,
+
+    /// With `--csv`, skip inputs already present in an existing `out_file`
+    /// whose recorded hash (of the file content and the filter settings) is
+    /// unchanged, so an interrupted bulk run can resume cheaply.
+    #[arg(long)]
+    update: bool,
+
+    /// Keep only relations with this `boundary` tag value. Repeatable.
+    /// Defaults to `DEFAULT_BOUNDARY_TYPES`.
+    #[arg(long)]
+    boundary_type: Vec<String>,
+
+    /// Keep only relations with this `admin_level` tag value. Repeatable,
+    /// and accepts an inclusive range such as `2..=8`. Defaults to
+    /// `DEFAULT_ADMIN_LEVELS`.
+    #[arg(long)]
+    admin_level: Vec<String>,
+
+    /// Path to a TOML or JSON file listing additional required tag
+    /// key/value constraints (see `TagConstraint`).
+    #[arg(long)]
+    filter_config: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+/// A `minlon,minlat,maxlon,maxlat` coordinate extent.
+#[derive(Clone, Copy, Debug)]
+struct BBox {
+    min_lon: f64,
+    min_lat: f64,
+    max_lon: f64,
+    max_lat: f64,
+}
+
+impl BBox {
+    fn intersects(&self, other: &BBox) -> bool {
+        self.min_lon <= other.max_lon
+            && self.max_lon >= other.min_lon
+            && self.min_lat <= other.max_lat
+            && self.max_lat >= other.min_lat
+    }
+}
+
+impl FromStr for BBox {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').collect();
+        let [min_lon, min_lat, max_lon, max_lat] = <[&str; 4]>::try_from(parts.as_slice())
+            .map_err(|_| format!("expected `minlon,minlat,maxlon,maxlat`, got {s:?}"))?;
+
+        let coord = |s: &str| {
+            s.trim()
+                .parse::<f64>()
+                .map_err(|e| format!("invalid coordinate {s:?}: {e}"))
+        };
+
+        Ok(BBox {
+            min_lon: coord(min_lon)?,
+            min_lat: coord(min_lat)?,
+            max_lon: coord(max_lon)?,
+            max_lat: coord(max_lat)?,
+        })
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     Stats,
+    /// Emit each target boundary relation as a line-delimited GeoJSON `Feature`.
+    Geojson,
+}
+
+/// A required `key == value` tag constraint loaded from `--filter-config`.
+#[derive(Deserialize, Clone)]
+struct TagConstraint {
+    key: String,
+    value: String,
+}
+
+/// A TOML or JSON `--filter-config` file.
+#[derive(Deserialize, Default)]
+struct FilterConfigFile {
+    #[serde(default)]
+    require_tags: Vec<TagConstraint>,
+}
+
+/// The relation-matching rules assembled from `--boundary-type`,
+/// `--admin-level` and `--filter-config`, replacing what used to be
+/// hardcoded constants.
+struct RelationFilter {
+    boundary_types: Vec<String>,
+    admin_levels: Vec<String>,
+    required_tags: Vec<TagConstraint>,
+}
+
+impl RelationFilter {
+    fn from_cli(cli: &Cli) -> Result<Self> {
+        let boundary_types = if cli.boundary_type.is_empty() {
+            DEFAULT_BOUNDARY_TYPES
+                .iter()
+                .map(|s| s.to_string())
+                .collect()
+        } else {
+            cli.boundary_type.clone()
+        };
+
+        let admin_levels = if cli.admin_level.is_empty() {
+            DEFAULT_ADMIN_LEVELS.iter().map(|s| s.to_string()).collect()
+        } else {
+            parse_admin_levels(&cli.admin_level)?
+        };
+
+        let required_tags = match &cli.filter_config {
+            Some(path) => load_filter_config(path)?,
+            None => Vec::new(),
+        };
+
+        Ok(RelationFilter {
+            boundary_types,
+            admin_levels,
+            required_tags,
+        })
+    }
+
+    /// Whether `obj` is a relation with a `name` tag matching the
+    /// admin-level and `--filter-config` constraints, irrespective of
+    /// boundary type.
+    fn matches_all(&self, obj: &OsmObj) -> bool {
+        obj.is_relation()
+            && obj.tags().contains_key("name")
+            && obj.tags().get("admin_level").is_some_and(|admin_level| {
+                self.admin_levels.iter().any(|level| level == admin_level)
+            })
+            && self.required_tags.iter().all(|constraint| {
+                obj.tags()
+                    .get(&constraint.key)
+                    .is_some_and(|value| value == &constraint.value)
+            })
+    }
+
+    /// Whether `obj` additionally has one of the configured `boundary`
+    /// types.
+    fn matches_target(&self, obj: &OsmObj) -> bool {
+        self.matches_all(obj)
+            && obj
+                .tags()
+                .get("boundary")
+                .is_some_and(|boundary| self.boundary_types.iter().any(|t| t == boundary))
+    }
+}
+
+/// Expands `--admin-level` values, supporting both discrete levels (`8`)
+/// and inclusive ranges (`2..=8`).
+fn parse_admin_levels(values: &[String]) -> Result<Vec<String>> {
+    let mut levels = Vec::new();
+
+    for value in values {
+        match value.split_once("..=") {
+            Some((lo, hi)) => {
+                let lo: i64 = lo.trim().parse()?;
+                let hi: i64 = hi.trim().parse()?;
+                anyhow::ensure!(
+                    lo <= hi,
+                    "invalid admin-level range `{lo}..={hi}`: lower bound must not exceed upper bound"
+                );
+                levels.extend((lo..=hi).map(|level| level.to_string()));
+            }
+            None => levels.push(value.clone()),
+        }
+    }
+
+    Ok(levels)
+}
+
+/// Loads the `require_tags` constraints from a TOML or JSON
+/// `--filter-config` file, picking the format from the file extension
+/// (defaulting to TOML).
+fn load_filter_config(path: &Path) -> Result<Vec<TagConstraint>> {
+    let content = std::fs::read_to_string(path)?;
+
+    let config: FilterConfigFile = if path.extension().and_then(|ext| ext.to_str()) == Some("json")
+    {
+        serde_json::from_str(&content)?
+    } else {
+        toml::from_str(&content)?
+    };
+
+    Ok(config.require_tags)
 }
 
 fn main() -> Result<()> {
@@ -47,75 +253,363 @@ fn main() -> Result<()> {
 
     info!("Unpacking relations from {:?}", cli.in_file);
 
-    let out: Box<dyn io::Write> = if let Some(f) = cli.out_file {
+    let filter = RelationFilter::from_cli(&cli)?;
+
+    if matches!(cli.command, Some(Commands::Stats)) && cli.csv {
+        return run_stats_csv(&cli, &filter);
+    }
+
+    let mut out: Box<dyn io::Write> = if let Some(f) = &cli.out_file {
         let f = std::fs::File::create(f)?;
         Box::new(f)
     } else {
         Box::new(stdout())
     };
 
-    if let Some(Commands::Stats) = cli.command {
-        let relations = load_relations(cli.in_file, filter_target_relations)?;
-        info!("Gathering some stats..");
-        to_stats(&relations, out)?;
-    } else {
-        let relations = load_relations(cli.in_file, filter_all_relations)?;
-        to_jsonl(&relations, out)?;
+    match cli.command {
+        Some(Commands::Stats) => {
+            // Combine counts across all inputs into a single report rather
+            // than emitting one ambiguous, unlabeled block per file.
+            let mut total_counts = HashMap::<String, usize>::new();
+
+            for path in &cli.in_file {
+                let relations =
+                    load_relations(path.clone(), |obj| filter.matches_target(obj), cli.jobs)?;
+                info!("Gathering some stats..");
+
+                for (boundary_type, count) in
+                    boundary_type_counts(&relations, cli.bbox.as_ref(), &filter)
+                {
+                    *total_counts.entry(boundary_type.to_string()).or_default() += count;
+                }
+            }
+
+            to_stats(&total_counts, &mut out)?;
+        }
+        Some(Commands::Geojson) => {
+            for path in &cli.in_file {
+                let relations =
+                    load_relations(path.clone(), |obj| filter.matches_target(obj), cli.jobs)?;
+                info!("Assembling boundary geometry..");
+                to_geojson(&relations, &mut out, cli.bbox.as_ref(), &filter)?;
+            }
+        }
+        None => {
+            for path in &cli.in_file {
+                let relations =
+                    load_relations(path.clone(), |obj| filter.matches_all(obj), cli.jobs)?;
+                to_jsonl(&relations, &mut out, cli.bbox.as_ref(), &filter)?;
+            }
+        }
     }
 
     Ok(())
 }
 
-fn filter_target_relations(obj: &OsmObj) -> bool {
-    filter_all_relations(obj)
-        && obj.tags().get("boundary").map_or(false, |boundary| {
-            TARGET_BOUNDARY_TYPES.contains(&boundary.as_str())
-        })
+/// Bulk `stats --csv`: writes one row per input file to `out_file` (or
+/// stdout), with `--update` skipping inputs whose content and effective
+/// filter settings already match a row in an existing output file.
+fn run_stats_csv(cli: &Cli, filter: &RelationFilter) -> Result<()> {
+    let existing = match (&cli.out_file, cli.update) {
+        (Some(path), true) => read_existing_csv_rows(path)?,
+        _ => HashMap::new(),
+    };
+    let resuming = is_resuming(
+        cli.update,
+        cli.out_file.as_ref().is_some_and(|path| path.exists()),
+    );
+
+    let mut out: Box<dyn io::Write> = match &cli.out_file {
+        Some(path) => Box::new(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(resuming)
+                .truncate(!resuming)
+                .open(path)?,
+        ),
+        None => Box::new(stdout()),
+    };
+
+    if !resuming {
+        writeln!(out, "{}", csv_header(filter))?;
+    }
+
+    // Folded into the row hash below, so resuming with different
+    // `--admin-level`/`--boundary-type`/`--filter-config`/`--bbox` flags
+    // reprocesses every input instead of skipping it. The new rows are
+    // appended after the existing ones, which stay in the file under its
+    // original header. `--jobs` is excluded: it only changes how the work
+    // is split, not the result.
+    let signature = filter_signature(filter, cli.bbox.as_ref());
+
+    for path in &cli.in_file {
+        let label = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.to_string_lossy().into_owned());
+        let hash = hash_input(path, &signature)?;
+
+        if cli.update && should_skip(&existing, &label, &hash) {
+            info!("skipping unchanged input {label}");
+            continue;
+        }
+
+        info!("processing {path:?}");
+        let relations = load_relations(path.clone(), |obj| filter.matches_target(obj), cli.jobs)?;
+        let counts = boundary_type_counts(&relations, cli.bbox.as_ref(), filter);
+
+        let columns = filter
+            .boundary_types
+            .iter()
+            .map(|boundary_type| {
+                counts
+                    .get(boundary_type.as_str())
+                    .copied()
+                    .unwrap_or(0)
+                    .to_string()
+            })
+            .join(",");
+
+        writeln!(out, "{},{hash},{columns}", csv_field(&label))?;
+    }
+
+    Ok(())
 }
 
-fn filter_all_relations(obj: &OsmObj) -> bool {
-    obj.is_relation()
-        && obj.tags().contains_key("name")
-        && obj.tags().get("admin_level").map_or(false, |admin_level| {
-            matches!(admin_level.as_str(), "2" | "4" | "6" | "7" | "8")
-        })
+/// Whether a `--update` run should append to `out_file` (and so omit the
+/// header) rather than truncate it and start fresh.
+fn is_resuming(update: bool, out_file_exists: bool) -> bool {
+    update && out_file_exists
+}
+
+/// Whether `label`'s previously recorded row hash still matches `hash`,
+/// i.e. neither the input's content nor the filter settings that produced
+/// its counts have changed since the last `--update` run.
+fn should_skip(existing: &HashMap<String, String>, label: &str, hash: &str) -> bool {
+    existing.get(label).map(String::as_str) == Some(hash)
+}
+
+/// The CSV header row: `label,sha3_256,<boundary type columns>`.
+fn csv_header(filter: &RelationFilter) -> String {
+    format!(
+        "label,sha3_256,{}",
+        filter.boundary_types.iter().map(|t| csv_field(t)).join(",")
+    )
+}
+
+/// A canonical representation of the filter settings that affect a
+/// `stats` row's counts (everything but `--jobs`). Folded into
+/// `hash_input`'s digest so `--update` reprocesses an input whose filter
+/// changed even when its content didn't.
+fn filter_signature(filter: &RelationFilter, bbox: Option<&BBox>) -> String {
+    let mut required_tags = filter
+        .required_tags
+        .iter()
+        .map(|constraint| format!("{}={}", constraint.key, constraint.value))
+        .collect::<Vec<_>>();
+    required_tags.sort();
+
+    format!(
+        "boundary_types={};admin_levels={};required_tags={};bbox={bbox:?}",
+        filter.boundary_types.join("|"),
+        filter.admin_levels.join("|"),
+        required_tags.join("|"),
+    )
+}
+
+/// Quotes `value` for a CSV field if it contains a comma, quote, or
+/// newline, doubling any embedded quotes (RFC 4180). Values without those
+/// characters, the common case here, are returned unchanged.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Splits one CSV line into fields, unescaping quoted fields written by
+/// `csv_field`.
+fn parse_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (in_quotes, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => in_quotes = false,
+            (true, c) => field.push(c),
+            (false, '"') => in_quotes = true,
+            (false, ',') => fields.push(std::mem::take(&mut field)),
+            (false, c) => field.push(c),
+        }
+    }
+    fields.push(field);
+
+    fields
+}
+
+/// Reads `label -> sha3_256` from a previously written `--csv` output file.
+fn read_existing_csv_rows(path: &PathBuf) -> Result<HashMap<String, String>> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    Ok(parse_existing_csv_rows(&std::fs::read_to_string(path)?))
+}
+
+/// Parses `label -> sha3_256` rows out of a `--csv` output file's
+/// contents, skipping the header and honoring quoted labels (see
+/// `csv_field`).
+fn parse_existing_csv_rows(content: &str) -> HashMap<String, String> {
+    let mut rows = HashMap::new();
+    for line in content.lines().skip(1) {
+        let columns = parse_csv_line(line);
+        if let (Some(label), Some(hash)) = (columns.first(), columns.get(1)) {
+            rows.insert(label.clone(), hash.clone());
+        }
+    }
+    rows
 }
 
-fn load_relations<F>(path: PathBuf, pred: F) -> Result<BTreeMap<OsmId, OsmObj>>
+/// Hex-encoded SHA3-256 digest of a file's contents combined with
+/// `filter_signature`, used by `--update` to detect inputs whose content
+/// or effective filter settings changed since a previous bulk run.
+fn hash_input(path: &Path, filter_signature: &str) -> Result<String> {
+    let bytes = std::fs::read(path)?;
+
+    let mut hasher = Sha3_256::new();
+    hasher.update(&bytes);
+    hasher.update(filter_signature.as_bytes());
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// Loads matching relations and their way/node dependencies, optionally
+/// spreading the work for `Stats`/`Geojson`/JSONL-sized extracts across a
+/// rayon thread pool sized by `jobs` (defaults to the number of CPUs).
+fn load_relations<F>(path: PathBuf, pred: F, jobs: Option<usize>) -> Result<BTreeMap<OsmId, OsmObj>>
+where
+    F: Fn(&OsmObj) -> bool + Sync + Send,
+{
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs.unwrap_or(0))
+        .build()?;
+
+    pool.install(|| load_relations_par(path, pred))
+}
+
+/// Three-pass parallel load: the first pass finds matching relations and the
+/// ids of the way/node members they need, the second harvests those
+/// members, and the third harvests the nodes the wanted ways themselves
+/// reference, each re-reading the same blocks. Each pass streams and filters blocks as they're decoded
+/// rather than materializing the whole file's decoded objects at once, so
+/// memory use stays proportional to what matches, not to the file size.
+fn load_relations_par<F>(path: PathBuf, pred: F) -> Result<BTreeMap<OsmId, OsmObj>>
+where
+    F: Fn(&OsmObj) -> bool + Sync + Send,
+{
+    let mut wanted = BTreeMap::new();
+    let mut wanted_members = HashSet::new();
+
+    for obj in stream_filtered(&path, |obj| pred(obj))? {
+        if let OsmObj::Relation(relation) = &obj {
+            wanted_members.extend(relation.refs.iter().map(|r| r.member));
+        }
+        wanted.insert(obj.id(), obj);
+    }
+
+    let mut wanted_nodes = HashSet::new();
+
+    for obj in stream_filtered(&path, |obj| wanted_members.contains(&obj.id()))? {
+        if let OsmObj::Way(way) = &obj {
+            wanted_nodes.extend(way.nodes.iter().map(|&id| OsmId::Node(id)));
+        }
+        wanted.insert(obj.id(), obj);
+    }
+
+    for obj in stream_filtered(&path, |obj| wanted_nodes.contains(&obj.id()))? {
+        wanted.insert(obj.id(), obj);
+    }
+
+    Ok(wanted)
+}
+
+/// Decodes and filters a PBF file's blocks in parallel, one block at a
+/// time, keeping only the objects `pred` matches. Blocks are never all held
+/// in memory at once, only the (much smaller) filtered result is.
+fn stream_filtered<F>(path: &Path, pred: F) -> Result<Vec<OsmObj>>
 where
-    F: FnMut(&OsmObj) -> bool,
+    F: Fn(&OsmObj) -> bool + Sync,
 {
     let f = std::fs::File::open(path)?;
     let mut pbf = OsmPbfReader::new(f);
-    let relations = pbf.get_objs_and_deps(pred)?;
-    Ok(relations)
+
+    let matched: Vec<Vec<OsmObj>> = pbf
+        .primitive_blocks()
+        .par_bridge()
+        .map(|block| -> Result<Vec<OsmObj>> {
+            Ok(block?
+                .into_objs()
+                .into_iter()
+                .filter(|obj| pred(obj))
+                .collect())
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(matched.into_iter().flatten().collect())
 }
 
-fn to_stats(relations: &BTreeMap<OsmId, OsmObj>, mut out: impl io::Write) -> Result<()> {
+/// Prints boundary-type counts, most common first.
+fn to_stats(counts: &HashMap<String, usize>, mut out: impl io::Write) -> Result<()> {
+    for (boundary_type, count) in counts.iter().sorted_by(|a, b| Ord::cmp(&b.1, &a.1)) {
+        writeln!(out, "{boundary_type} {count}")?;
+    }
+
+    Ok(())
+}
+
+/// Counts relations by their `boundary` tag value.
+fn boundary_type_counts<'a>(
+    relations: &'a BTreeMap<OsmId, OsmObj>,
+    bbox: Option<&BBox>,
+    filter: &RelationFilter,
+) -> HashMap<&'a str, usize> {
     let mut boundary_types = HashMap::<&str, usize>::new();
 
     for boundary in relations
         .values()
-        .filter(|obj| filter_all_relations(obj))
+        .filter(|obj| filter.matches_all(obj) && passes_bbox(obj, relations, bbox))
         .filter_map(|obj| obj.tags().get("boundary"))
     {
         *boundary_types.entry(boundary).or_default() += 1;
     }
 
-    for (boundary_type, count) in boundary_types.iter().sorted_by(|a, b| Ord::cmp(&b.1, &a.1)) {
-        writeln!(out, "{boundary_type} {count}")?;
-    }
-
-    Ok(())
+    boundary_types
 }
 
-fn to_jsonl(relations: &BTreeMap<OsmId, OsmObj>, out: impl io::Write) -> Result<()> {
+fn to_jsonl(
+    relations: &BTreeMap<OsmId, OsmObj>,
+    out: impl io::Write,
+    bbox: Option<&BBox>,
+    filter: &RelationFilter,
+) -> Result<()> {
     // Use a buffered writer to amortize flushes.
     let mut buffer = BufWriter::new(out);
 
     for relation in relations
         .values()
-        .filter(|obj| filter_target_relations(obj))
+        .filter(|obj| filter.matches_target(obj) && passes_bbox(obj, relations, bbox))
     {
         let serialized = to_string(&relation)?;
         writeln!(buffer, "{serialized}")?;
@@ -123,3 +617,505 @@ fn to_jsonl(relations: &BTreeMap<OsmId, OsmObj>, out: impl io::Write) -> Result<
 
     Ok(())
 }
+
+/// Writes one line-delimited GeoJSON `Feature` per target boundary relation.
+fn to_geojson(
+    relations: &BTreeMap<OsmId, OsmObj>,
+    out: impl io::Write,
+    bbox: Option<&BBox>,
+    filter: &RelationFilter,
+) -> Result<()> {
+    let mut buffer = BufWriter::new(out);
+
+    for obj in relations
+        .values()
+        .filter(|obj| filter.matches_target(obj) && passes_bbox(obj, relations, bbox))
+    {
+        let OsmObj::Relation(relation) = obj else {
+            continue;
+        };
+
+        let Some(geometry) = relation_geometry(relation, relations) else {
+            warn!(
+                "relation {:?} has no closed ring geometry, skipping",
+                relation.id
+            );
+            continue;
+        };
+
+        let properties: serde_json::Map<String, Value> = relation
+            .tags
+            .iter()
+            .map(|(k, v)| (k.to_string(), Value::String(v.to_string())))
+            .collect();
+
+        let feature = json!({
+            "type": "Feature",
+            "geometry": geometry,
+            "properties": properties,
+        });
+
+        writeln!(buffer, "{feature}")?;
+    }
+
+    Ok(())
+}
+
+/// Whether `obj` should be kept for a given `--bbox`. Always `true` when no
+/// `bbox` was requested.
+fn passes_bbox(obj: &OsmObj, relations: &BTreeMap<OsmId, OsmObj>, bbox: Option<&BBox>) -> bool {
+    let Some(bbox) = bbox else {
+        return true;
+    };
+    let OsmObj::Relation(relation) = obj else {
+        return false;
+    };
+
+    relation_extent(relation, relations).is_some_and(|extent| bbox.intersects(&extent))
+}
+
+/// Computes the coordinate extent of a relation by walking its member ways
+/// down to their node `lon()`/`lat()` values.
+fn relation_extent(relation: &Relation, relations: &BTreeMap<OsmId, OsmObj>) -> Option<BBox> {
+    let mut extent: Option<BBox> = None;
+
+    for member in &relation.refs {
+        let OsmId::Way(way_id) = member.member else {
+            continue;
+        };
+        let Some(OsmObj::Way(way)) = relations.get(&OsmId::Way(way_id)) else {
+            continue;
+        };
+
+        for &node_id in &way.nodes {
+            let Some(OsmObj::Node(node)) = relations.get(&OsmId::Node(node_id)) else {
+                continue;
+            };
+            let (lon, lat) = (node.lon(), node.lat());
+
+            extent = Some(match extent {
+                Some(e) => BBox {
+                    min_lon: e.min_lon.min(lon),
+                    min_lat: e.min_lat.min(lat),
+                    max_lon: e.max_lon.max(lon),
+                    max_lat: e.max_lat.max(lat),
+                },
+                None => BBox {
+                    min_lon: lon,
+                    min_lat: lat,
+                    max_lon: lon,
+                    max_lat: lat,
+                },
+            });
+        }
+    }
+
+    extent
+}
+
+/// Stitches a relation's `outer`/`inner` member ways into a `Polygon` or
+/// `MultiPolygon` geometry, resolving way node ids through `relations`.
+///
+/// Returns `None` if the relation has no way that stitches into a closed
+/// outer ring.
+fn relation_geometry(relation: &Relation, relations: &BTreeMap<OsmId, OsmObj>) -> Option<Value> {
+    let mut outer_ways = Vec::new();
+    let mut inner_ways = Vec::new();
+
+    for member in &relation.refs {
+        let OsmId::Way(way_id) = member.member else {
+            continue;
+        };
+        // Filter out members whose way is missing from the loaded
+        // dependency map, like osm-geo-mapper does. A way with no nodes at
+        // all is just as unusable, so skip it the same way. Rings with a
+        // missing node are dropped after stitching by `ring_coordinates`.
+        let Some(OsmObj::Way(way)) = relations.get(&OsmId::Way(way_id)) else {
+            continue;
+        };
+        if way.nodes.is_empty() {
+            continue;
+        }
+
+        match member.role.as_str() {
+            "outer" => outer_ways.push(way.nodes.clone()),
+            "inner" => inner_ways.push(way.nodes.clone()),
+            _ => {}
+        }
+    }
+
+    let (outer_rings, outer_open) = stitch_way_rings(outer_ways);
+    let (inner_rings, inner_open) = stitch_way_rings(inner_ways);
+
+    if !outer_open.is_empty() || !inner_open.is_empty() {
+        warn!(
+            "relation {:?}: {} outer and {} inner way chain(s) never closed into a ring",
+            relation.id,
+            outer_open.len(),
+            inner_open.len()
+        );
+    }
+
+    let outer_rings: Vec<Vec<(f64, f64)>> = outer_rings
+        .iter()
+        .filter_map(|ring| ring_coordinates(ring, relations))
+        .collect();
+    let inner_rings: Vec<Vec<(f64, f64)>> = inner_rings
+        .iter()
+        .filter_map(|ring| ring_coordinates(ring, relations))
+        .collect();
+
+    if outer_rings.is_empty() {
+        return None;
+    }
+
+    let shells = assign_holes_to_shells(outer_rings, inner_rings);
+
+    let polygons: Vec<Value> = shells
+        .into_iter()
+        .map(|(shell, holes)| {
+            let mut rings = vec![ring_to_json(&shell)];
+            rings.extend(holes.iter().map(|hole| ring_to_json(hole)));
+            Value::Array(rings)
+        })
+        .collect();
+
+    Some(if polygons.len() == 1 {
+        json!({ "type": "Polygon", "coordinates": polygons.into_iter().next().unwrap() })
+    } else {
+        json!({ "type": "MultiPolygon", "coordinates": polygons })
+    })
+}
+
+/// Greedily stitches way node-id chains into closed rings.
+///
+/// Repeatedly takes an unused chain and extends it by finding a remaining
+/// chain whose first or last node matches the open endpoint, reversing the
+/// chain when it matches tail-to-tail. Chains that never close are returned
+/// separately.
+fn stitch_way_rings(mut segments: Vec<Vec<NodeId>>) -> (Vec<Vec<NodeId>>, Vec<Vec<NodeId>>) {
+    let mut closed = Vec::new();
+    let mut open = Vec::new();
+
+    while !segments.is_empty() {
+        let mut ring = segments.remove(0);
+
+        loop {
+            if ring.len() > 1 && ring.first() == ring.last() {
+                closed.push(ring);
+                break;
+            }
+
+            let tail = *ring.last().expect("ring is never empty");
+            let head_match = segments.iter().position(|seg| seg.first() == Some(&tail));
+            let tail_match = segments.iter().position(|seg| seg.last() == Some(&tail));
+
+            if let Some(i) = head_match {
+                let mut seg = segments.remove(i);
+                seg.remove(0);
+                ring.extend(seg);
+            } else if let Some(i) = tail_match {
+                let mut seg = segments.remove(i);
+                seg.pop();
+                seg.reverse();
+                ring.extend(seg);
+            } else {
+                open.push(ring);
+                break;
+            }
+        }
+    }
+
+    (closed, open)
+}
+
+/// Resolves a ring's node ids to `(lon, lat)` pairs, or `None` if any node is
+/// missing from the dependency map.
+fn ring_coordinates(
+    ring: &[NodeId],
+    relations: &BTreeMap<OsmId, OsmObj>,
+) -> Option<Vec<(f64, f64)>> {
+    ring.iter()
+        .map(|&node_id| match relations.get(&OsmId::Node(node_id)) {
+            Some(OsmObj::Node(node)) => Some((node.lon(), node.lat())),
+            _ => None,
+        })
+        .collect()
+}
+
+fn ring_to_json(ring: &[(f64, f64)]) -> Value {
+    json!(ring
+        .iter()
+        .map(|&(lon, lat)| vec![lon, lat])
+        .collect::<Vec<_>>())
+}
+
+/// Assigns each hole to the outer ring that contains it, falling back to
+/// the first outer ring if none of them do. Returns one `(shell, holes)`
+/// pair per outer ring.
+fn assign_holes_to_shells(
+    outer_rings: Vec<Vec<(f64, f64)>>,
+    inner_rings: Vec<Vec<(f64, f64)>>,
+) -> Vec<(Vec<(f64, f64)>, Vec<Vec<(f64, f64)>>)> {
+    let mut shells: Vec<(Vec<(f64, f64)>, Vec<Vec<(f64, f64)>>)> = outer_rings
+        .into_iter()
+        .map(|ring| (ring, Vec::new()))
+        .collect();
+
+    for hole in inner_rings {
+        let containing_shell = hole.first().and_then(|&point| {
+            shells
+                .iter_mut()
+                .find(|(ring, _)| point_in_ring(point, ring))
+        });
+
+        match containing_shell {
+            Some(shell) => shell.1.push(hole),
+            None => shells[0].1.push(hole),
+        }
+    }
+
+    shells
+}
+
+/// Even-odd (ray casting) point-in-polygon test, used to match holes to the
+/// outer ring that contains them.
+fn point_in_ring(point: (f64, f64), ring: &[(f64, f64)]) -> bool {
+    let (x, y) = point;
+    let mut inside = false;
+
+    for (&(x1, y1), &(x2, y2)) in ring.iter().tuple_windows() {
+        let intersects = ((y1 > y) != (y2 > y)) && (x < (x2 - x1) * (y - y1) / (y2 - y1) + x1);
+        if intersects {
+            inside = !inside;
+        }
+    }
+
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stitch_way_rings_closes_a_ring() {
+        let segments = vec![
+            vec![NodeId(1), NodeId(2)],
+            vec![NodeId(2), NodeId(3)],
+            vec![NodeId(3), NodeId(1)],
+        ];
+
+        let (closed, open) = stitch_way_rings(segments);
+
+        assert_eq!(closed.len(), 1);
+        assert!(open.is_empty());
+        assert_eq!(closed[0].first(), closed[0].last());
+    }
+
+    #[test]
+    fn stitch_way_rings_leaves_an_open_chain_open() {
+        let segments = vec![vec![NodeId(1), NodeId(2)], vec![NodeId(2), NodeId(3)]];
+
+        let (closed, open) = stitch_way_rings(segments);
+
+        assert!(closed.is_empty());
+        assert_eq!(open, vec![vec![NodeId(1), NodeId(2), NodeId(3)]]);
+    }
+
+    #[test]
+    fn stitch_way_rings_reverses_a_tail_to_tail_match() {
+        let segments = vec![
+            vec![NodeId(1), NodeId(2)],
+            vec![NodeId(3), NodeId(2)],
+            vec![NodeId(3), NodeId(1)],
+        ];
+
+        let (closed, open) = stitch_way_rings(segments);
+
+        assert_eq!(closed.len(), 1);
+        assert!(open.is_empty());
+        assert_eq!(closed[0].first(), closed[0].last());
+    }
+
+    #[test]
+    fn point_in_ring_detects_inside_and_outside_points() {
+        let square = vec![(0.0, 0.0), (4.0, 0.0), (4.0, 4.0), (0.0, 4.0), (0.0, 0.0)];
+
+        assert!(point_in_ring((2.0, 2.0), &square));
+        assert!(!point_in_ring((5.0, 5.0), &square));
+    }
+
+    #[test]
+    fn assign_holes_to_shells_picks_the_containing_shell() {
+        let near_shell = vec![(0.0, 0.0), (4.0, 0.0), (4.0, 4.0), (0.0, 4.0), (0.0, 0.0)];
+        let far_shell = vec![
+            (10.0, 10.0),
+            (14.0, 10.0),
+            (14.0, 14.0),
+            (10.0, 14.0),
+            (10.0, 10.0),
+        ];
+        let hole = vec![(1.0, 1.0), (2.0, 1.0), (2.0, 2.0), (1.0, 2.0), (1.0, 1.0)];
+
+        let shells = assign_holes_to_shells(
+            vec![near_shell.clone(), far_shell.clone()],
+            vec![hole.clone()],
+        );
+
+        assert_eq!(shells.len(), 2);
+        assert_eq!(shells[0].0, near_shell);
+        assert_eq!(shells[0].1, vec![hole]);
+        assert_eq!(shells[1].0, far_shell);
+        assert!(shells[1].1.is_empty());
+    }
+
+    #[test]
+    fn assign_holes_to_shells_falls_back_to_first_shell_when_unmatched() {
+        let shell = vec![(0.0, 0.0), (4.0, 0.0), (4.0, 4.0), (0.0, 4.0), (0.0, 0.0)];
+        let stray_hole = vec![
+            (20.0, 20.0),
+            (21.0, 20.0),
+            (21.0, 21.0),
+            (20.0, 21.0),
+            (20.0, 20.0),
+        ];
+
+        let shells = assign_holes_to_shells(vec![shell.clone()], vec![stray_hole.clone()]);
+
+        assert_eq!(shells.len(), 1);
+        assert_eq!(shells[0].1, vec![stray_hole]);
+    }
+
+    #[test]
+    fn parse_admin_levels_expands_ranges_and_keeps_discrete_values() {
+        let levels = parse_admin_levels(&["2..=4".to_string(), "8".to_string()]).unwrap();
+
+        assert_eq!(levels, vec!["2", "3", "4", "8"]);
+    }
+
+    #[test]
+    fn parse_admin_levels_rejects_a_reversed_range() {
+        assert!(parse_admin_levels(&["8..=2".to_string()]).is_err());
+    }
+
+    #[test]
+    fn bbox_from_str_parses_and_intersects() {
+        let a: BBox = "0,0,10,10".parse().unwrap();
+        let b: BBox = "5,5,15,15".parse().unwrap();
+        let c: BBox = "20,20,30,30".parse().unwrap();
+
+        assert!(a.intersects(&b));
+        assert!(!a.intersects(&c));
+    }
+
+    #[test]
+    fn bbox_from_str_rejects_malformed_input() {
+        assert!("0,0,10".parse::<BBox>().is_err());
+        assert!("a,0,10,10".parse::<BBox>().is_err());
+    }
+
+    #[test]
+    fn csv_field_quotes_only_when_necessary() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("with,comma"), "\"with,comma\"");
+        assert_eq!(csv_field("with\"quote"), "\"with\"\"quote\"");
+    }
+
+    #[test]
+    fn parse_csv_line_round_trips_a_quoted_label() {
+        let line = format!("{},deadbeef,1,2", csv_field("berlin,de"));
+
+        let fields = parse_csv_line(&line);
+
+        assert_eq!(fields, vec!["berlin,de", "deadbeef", "1", "2"]);
+    }
+
+    #[test]
+    fn parse_existing_csv_rows_skips_the_header_and_unquotes_labels() {
+        let content = "label,sha3_256,administrative\n\
+             \"berlin,de\",deadbeef,1\n\
+             hamburg,cafef00d,2\n";
+
+        let rows = parse_existing_csv_rows(content);
+
+        assert_eq!(rows.get("berlin,de").map(String::as_str), Some("deadbeef"));
+        assert_eq!(rows.get("hamburg").map(String::as_str), Some("cafef00d"));
+    }
+
+    #[test]
+    fn csv_header_quotes_boundary_type_columns() {
+        let cli = Cli::parse_from([
+            "osmtools",
+            "-i",
+            "in.pbf",
+            "--boundary-type",
+            "admin,special",
+        ]);
+        let filter = RelationFilter::from_cli(&cli).unwrap();
+
+        assert_eq!(csv_header(&filter), "label,sha3_256,\"admin,special\"");
+    }
+
+    #[test]
+    fn is_resuming_requires_both_update_and_an_existing_file() {
+        assert!(!is_resuming(false, true));
+        assert!(!is_resuming(true, false));
+        assert!(is_resuming(true, true));
+    }
+
+    #[test]
+    fn should_skip_matches_only_on_an_identical_hash() {
+        let mut existing = HashMap::new();
+        existing.insert("berlin".to_string(), "deadbeef".to_string());
+
+        assert!(should_skip(&existing, "berlin", "deadbeef"));
+        assert!(!should_skip(&existing, "berlin", "cafef00d"));
+        assert!(!should_skip(&existing, "hamburg", "deadbeef"));
+    }
+
+    #[test]
+    fn filter_signature_changes_with_admin_level_but_not_jobs() {
+        let base = Cli::parse_from(["osmtools", "-i", "in.pbf", "--admin-level", "8"]);
+        let changed = Cli::parse_from(["osmtools", "-i", "in.pbf", "--admin-level", "6"]);
+        let more_jobs = Cli::parse_from([
+            "osmtools",
+            "-i",
+            "in.pbf",
+            "--admin-level",
+            "8",
+            "--jobs",
+            "4",
+        ]);
+
+        let base_filter = RelationFilter::from_cli(&base).unwrap();
+        let changed_filter = RelationFilter::from_cli(&changed).unwrap();
+        let more_jobs_filter = RelationFilter::from_cli(&more_jobs).unwrap();
+
+        assert_ne!(
+            filter_signature(&base_filter, None),
+            filter_signature(&changed_filter, None)
+        );
+        assert_eq!(
+            filter_signature(&base_filter, None),
+            filter_signature(&more_jobs_filter, None)
+        );
+    }
+
+    #[test]
+    fn hash_input_changes_when_the_filter_signature_changes() {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "osmtools-hash-input-test-{:?}",
+            std::thread::current().id()
+        ));
+        std::fs::write(&path, b"some file contents").unwrap();
+
+        let a = hash_input(&path, "boundary_types=administrative").unwrap();
+        let b = hash_input(&path, "boundary_types=state_border").unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert_ne!(a, b);
+    }
+}